@@ -0,0 +1,144 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::Context;
+use socketcan::{CanAnyFrame, CanFdFrame, CanFrame, EmbeddedFrame, Frame, Id};
+
+/// Appends frames to a log file in the standard candump line format:
+/// `(<epoch>.<micros>) <iface> <ID>#<hexdata>`.
+pub struct CandumpWriter {
+    file: File,
+    iface: String,
+}
+
+impl CandumpWriter {
+    pub fn create(path: &str, iface: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening log file: {}", path))?;
+        Ok(Self {
+            file,
+            iface: iface.to_string(),
+        })
+    }
+
+    pub fn log(&mut self, frame: &CanAnyFrame, timestamp: Duration) -> anyhow::Result<()> {
+        let line = match frame {
+            CanAnyFrame::Normal(CanFrame::Data(d)) => {
+                format!("{}#{}", format_id(d.id()), hex_encode(d.data()))
+            }
+            CanAnyFrame::Normal(CanFrame::Remote(r)) => {
+                format!("{}#R{}", format_id(r.id()), r.dlc())
+            }
+            CanAnyFrame::Normal(CanFrame::Error(_)) => return Ok(()),
+            CanAnyFrame::Fd(f) => {
+                format!("{}##{:x}{}", format_id(f.id()), fd_flags_nibble(f), hex_encode(f.data()))
+            }
+        };
+
+        writeln!(
+            self.file,
+            "({}.{:06}) {} {}",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            self.iface,
+            line
+        )
+        .context("writing candump log")?;
+        Ok(())
+    }
+}
+
+fn fd_flags_nibble(f: &CanFdFrame) -> u8 {
+    f.flags().bits()
+}
+
+/// Renders an arbitration ID the way candump does: the bare numeric value
+/// with no RTR/ERR/EFF flag bits, as 3 hex digits for standard IDs or 8 for
+/// extended IDs.
+fn format_id(id: Id) -> String {
+    match id {
+        Id::Standard(id) => format!("{:03x}", id.as_raw()),
+        Id::Extended(id) => format!("{:08x}", id.as_raw()),
+    }
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        anyhow::bail!("odd-length hex data: {}", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).with_context(|| format!("invalid hex byte: {}", &s[i..i + 2])))
+        .collect()
+}
+
+/// One parsed line of a candump log.
+pub struct LogEntry {
+    pub timestamp: Duration,
+    pub iface: String,
+    pub frame: CanAnyFrame,
+}
+
+/// Parses a single candump log line, e.g. `(1660000000.123456) can0 123#DEADBEEF`.
+pub fn parse_line(line: &str) -> anyhow::Result<LogEntry> {
+    let line = line.trim();
+
+    let ts_end = line.find(')').context("missing closing ')' after timestamp")?;
+    let ts_str = line
+        .strip_prefix('(')
+        .context("missing opening '(' before timestamp")?
+        .get(..ts_end - 1)
+        .context("malformed timestamp")?;
+    let (secs_str, micros_str) = ts_str.split_once('.').context("timestamp missing fractional seconds")?;
+    let timestamp = Duration::new(
+        secs_str.parse().context("invalid timestamp seconds")?,
+        micros_str.parse::<u32>().context("invalid timestamp micros")? * 1000,
+    );
+
+    let rest = line[ts_end + 1..].trim_start();
+    let (iface, rest) = rest.split_once(' ').context("missing interface name")?;
+
+    if let Some((id_str, payload)) = rest.split_once("##") {
+        let id = u32::from_str_radix(id_str, 16).context("invalid CAN id")?;
+        let flags_char = payload.chars().next().context("FD frame missing flags nibble")?;
+        let flags = flags_char.to_digit(16).context("invalid FD flags nibble")? as u8;
+        let data = hex_decode(&payload[1..])?;
+
+        let mut fd_frame = socketcan::frame::canfd_frame_default();
+        fd_frame.can_id = id;
+        fd_frame.len = data.len() as u8;
+        fd_frame.flags = flags;
+        fd_frame.data[..data.len()].copy_from_slice(&data);
+
+        return Ok(LogEntry {
+            timestamp,
+            iface: iface.to_string(),
+            frame: CanAnyFrame::Fd(CanFdFrame::from(fd_frame)),
+        });
+    }
+
+    let (id_str, payload) = rest.split_once('#').context("missing '#' before frame data")?;
+    let id = u32::from_str_radix(id_str, 16).context("invalid CAN id")?;
+
+    let frame = if let Some(dlc_str) = payload.strip_prefix('R') {
+        let dlc: usize = if dlc_str.is_empty() { 0 } else { dlc_str.parse().context("invalid remote frame DLC")? };
+        CanFrame::remote_from_raw_id(id, dlc).context("building remote frame")?
+    } else {
+        let data = hex_decode(payload)?;
+        CanFrame::from_raw_id(id, &data).context("building data frame")?
+    };
+
+    Ok(LogEntry {
+        timestamp,
+        iface: iface.to_string(),
+        frame: CanAnyFrame::Normal(frame),
+    })
+}
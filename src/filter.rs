@@ -0,0 +1,114 @@
+use anyhow::Context;
+use clap::ValueEnum;
+use socketcan::CanFilter;
+use tracing::debug;
+
+/// How `FrameFilter` rules are interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FilterMode {
+    /// Only frames matching a rule are let through.
+    Whitelist,
+    /// Frames matching a rule are dropped; everything else is let through.
+    Blacklist,
+}
+
+/// A single `ID:MASK` rule (hex), optionally inverted with a leading `!`.
+#[derive(Clone, Copy, Debug)]
+pub struct FilterRule {
+    id: u32,
+    mask: u32,
+    invert: bool,
+}
+
+impl FilterRule {
+    /// Parses a rule of the form `ID:MASK` or `!ID:MASK`, both in hex.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (rest, invert) = match s.strip_prefix('!') {
+            Some(rest) => (rest, true),
+            None => (s, false),
+        };
+
+        let (id_str, mask_str) = rest
+            .split_once(':')
+            .with_context(|| format!("filter rule must be ID:MASK, got: {}", s))?;
+
+        let id = u32::from_str_radix(id_str.trim_start_matches("0x"), 16)
+            .with_context(|| format!("invalid filter id: {}", id_str))?;
+        let mask = u32::from_str_radix(mask_str.trim_start_matches("0x"), 16)
+            .with_context(|| format!("invalid filter mask: {}", mask_str))?;
+
+        Ok(Self { id, mask, invert })
+    }
+
+    fn matches(&self, can_id: u32) -> bool {
+        (can_id & self.mask) == (self.id & self.mask)
+    }
+
+    fn as_kernel_filter(&self) -> CanFilter {
+        if self.invert {
+            CanFilter::new_inverted(self.id, self.mask)
+        } else {
+            CanFilter::new(self.id, self.mask)
+        }
+    }
+}
+
+/// An allow/deny list of `FilterRule`s, used to decide whether a frame with
+/// a given CAN ID should be forwarded.
+#[derive(Clone, Debug, Default)]
+pub struct FrameFilter {
+    mode: Option<FilterMode>,
+    rules: Vec<FilterRule>,
+}
+
+impl FrameFilter {
+    pub fn new(mode: FilterMode, rules: Vec<FilterRule>) -> Self {
+        Self {
+            mode: Some(mode),
+            rules,
+        }
+    }
+
+    /// No rules configured: every frame is let through.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Kernel-level `CanFilter`s for the rules that can be expressed as a
+    /// simple match-to-pass set, i.e. whitelist mode. SocketCAN ORs multiple
+    /// filters together (a frame passes if it matches *any* of them), which
+    /// cannot express "pass unless it matches one of these" for more than a
+    /// single blacklist rule, so blacklist mode is enforced in userspace only
+    /// (see `allows`) and installs no kernel filters.
+    pub fn kernel_filters(&self) -> Vec<CanFilter> {
+        match self.mode {
+            Some(FilterMode::Whitelist) => {
+                self.rules.iter().map(FilterRule::as_kernel_filter).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether a frame with the given CAN ID should be forwarded.
+    pub fn allows(&self, can_id: u32) -> bool {
+        let Some(mode) = self.mode else {
+            return true;
+        };
+
+        let matched = self.rules.iter().find(|r| r.matches(can_id) != r.invert);
+
+        match (mode, matched) {
+            (FilterMode::Whitelist, Some(rule)) => {
+                debug!("filter: {:?} allowed id {:08x}", rule, can_id);
+                true
+            }
+            (FilterMode::Whitelist, None) => false,
+
+            (FilterMode::Blacklist, Some(rule)) => {
+                debug!("filter: {:?} blocked id {:08x}", rule, can_id);
+                false
+            }
+            (FilterMode::Blacklist, None) => true,
+        }
+    }
+}
@@ -1,78 +1,313 @@
 use bytes::{Buf, BufMut, BytesMut};
-use socketcan::frame::IdFlags;
-use socketcan::{CanFrame, EmbeddedFrame, Frame};
+use socketcan::frame::{FdFlags, IdFlags};
+use socketcan::{CanAnyFrame, CanFdFrame, CanFrame, EmbeddedFrame, Frame};
 use tokio_util::codec::Decoder;
 use tokio_util::codec::Encoder;
 
-pub struct CanFrameCodec;
+/// Bit set in the wire-format flags byte when the frame is a CAN FD frame
+/// rather than a classic CAN frame.
+const FLAG_FD: u8 = 0x01;
+/// Bit rate switch, mirrored from `FdFlags::BRS`.
+const FLAG_BRS: u8 = 0x02;
+/// Error state indicator, mirrored from `FdFlags::ESI`.
+const FLAG_ESI: u8 = 0x04;
+
+/// Two-byte magic marking the start of a frame in the self-synchronizing
+/// wire format. Chosen to be unlikely to occur inside a frame body by chance.
+const SYNC_WORD: [u8; 2] = [0xaa, 0x55];
+
+/// `id(4) + len(1) + flags(1)` header preceding the payload in a frame body.
+const BODY_HEADER_LEN: usize = 6;
+/// `sync(2) + body_len(1)` preceding the body in the self-synchronizing format.
+const SYNC_HEADER_LEN: usize = 3;
+/// `id(4) + dlc(1)` header used by the legacy wire format, which predates CAN
+/// FD support and so carries no flags byte.
+const LEGACY_HEADER_LEN: usize = 5;
+
+/// CRC-8 (poly 0x07, init 0x00) over the self-synchronizing format's
+/// length byte and body, used to reject false-positive sync-word matches.
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Codec for framing `CanFrame`/`CanFdFrame` values over a byte stream.
+///
+/// By default frames are self-synchronizing: each is wrapped in a sync word,
+/// length and CRC-8 so a corrupted or misaligned stream can resynchronize at
+/// the next intact frame boundary instead of desyncing permanently. Use
+/// [`CanFrameCodec::legacy`] to speak the older raw format (no sync/CRC,
+/// no flags byte, no CAN FD support) for back-compat with peers running a
+/// version of this codec from before CAN FD support was added.
+pub struct CanFrameCodec {
+    legacy: bool,
+}
+
+impl CanFrameCodec {
+    /// Self-synchronizing framing (default).
+    pub fn new() -> Self {
+        Self { legacy: false }
+    }
+
+    /// Raw framing with no sync word or CRC, matching the 5-byte
+    /// `id(4)+dlc(1)` header this codec used before CAN FD support (and the
+    /// flags byte it required) was added. No FD frames.
+    pub fn legacy() -> Self {
+        Self { legacy: true }
+    }
+
+    fn write_frame(&self, body: &[u8], dst: &mut BytesMut) {
+        if self.legacy {
+            // Drop the flags byte: the legacy wire format predates it.
+            dst.reserve(LEGACY_HEADER_LEN + (body.len() - BODY_HEADER_LEN));
+            dst.put_slice(&body[..4]);
+            dst.put_u8(body[4]);
+            dst.put_slice(&body[BODY_HEADER_LEN..]);
+            return;
+        }
+
+        dst.reserve(SYNC_HEADER_LEN + body.len() + 1);
+        dst.put_slice(&SYNC_WORD);
+        dst.put_u8(body.len() as u8);
+        dst.put_slice(body);
+        dst.put_u8(crc8(&[&[body.len() as u8][..], body].concat()));
+    }
+}
+
+impl Default for CanFrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_body(body: &[u8]) -> Option<CanAnyFrame> {
+    if body.len() < BODY_HEADER_LEN {
+        return None;
+    }
+
+    let mut id_bytes = [0_u8; 4];
+    id_bytes.copy_from_slice(&body[..4]);
+    let can_id = u32::from_be_bytes(id_bytes);
+
+    let len = body[4] as usize;
+    let flags = body[5];
+    let is_fd = flags & FLAG_FD != 0;
+    let id_flags = IdFlags::from_bits_truncate(can_id);
+    // Remote frames carry the DLC in the length field but no trailing data
+    // bytes on the wire, so the payload size used for framing is 0 for them.
+    let payload_len = if !is_fd && id_flags.contains(IdFlags::RTR) { 0 } else { len };
+
+    // `len` comes straight off the wire; cap it to the fixed-size frame
+    // buffers below before it's ever used as a slice index, so a corrupt or
+    // malicious length byte can't panic the decoder.
+    let max_len = if is_fd { 64 } else { 8 };
+    if len > max_len {
+        return None;
+    }
+
+    if body.len() != BODY_HEADER_LEN + payload_len {
+        return None;
+    }
+
+    if is_fd {
+        let mut fd_frame = socketcan::frame::canfd_frame_default();
+        fd_frame.can_id = can_id;
+        fd_frame.len = len as u8;
+        if flags & FLAG_BRS != 0 {
+            fd_frame.flags |= FdFlags::BRS.bits();
+        }
+        if flags & FLAG_ESI != 0 {
+            fd_frame.flags |= FdFlags::ESI.bits();
+        }
+        fd_frame.data[..len].copy_from_slice(&body[BODY_HEADER_LEN..]);
+
+        Some(CanAnyFrame::Fd(CanFdFrame::from(fd_frame)))
+    } else {
+        let mut can_frame = socketcan::frame::can_frame_default();
+        can_frame.can_id = can_id;
+        can_frame.can_dlc = len as u8;
+
+        if !id_flags.contains(IdFlags::RTR) {
+            can_frame.data[..len].copy_from_slice(&body[BODY_HEADER_LEN..]);
+        }
+
+        Some(CanAnyFrame::Normal(CanFrame::from(can_frame)))
+    }
+}
 
 impl Encoder<CanFrame> for CanFrameCodec {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: CanFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut body = BytesMut::new();
         match item {
             CanFrame::Data(d) => {
                 let len = d.len();
-                dst.reserve(5 + len);
+                body.reserve(BODY_HEADER_LEN + len);
 
-                dst.put_u32(d.id_word());
-                dst.put_u8(len as u8);
-
-                let data = d.data();
-                dst.put_slice(data);
-                Ok(())
+                body.put_u32(d.id_word());
+                body.put_u8(len as u8);
+                body.put_u8(0); // classic frame: no FD flags
+                body.put_slice(d.data());
             }
 
             CanFrame::Remote(r) => {
-                let len = r.len();
-                dst.reserve(5);
+                body.reserve(BODY_HEADER_LEN);
 
-                dst.put_u32(r.id_word());
-                dst.put_u8(len as u8);
-                Ok(())
+                body.put_u32(r.id_word());
+                body.put_u8(r.len() as u8);
+                body.put_u8(0);
             }
 
             CanFrame::Error(e) => {
                 todo!()
             }
         }
+
+        self.write_frame(&body, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<CanFdFrame> for CanFrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: CanFdFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if self.legacy {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "legacy framing predates CAN FD support",
+            ));
+        }
+
+        let len = item.len();
+        let mut body = BytesMut::with_capacity(BODY_HEADER_LEN + len);
+
+        let mut flags = FLAG_FD;
+        if item.flags().contains(FdFlags::BRS) {
+            flags |= FLAG_BRS;
+        }
+        if item.flags().contains(FdFlags::ESI) {
+            flags |= FLAG_ESI;
+        }
+
+        body.put_u32(item.id_word());
+        body.put_u8(len as u8);
+        body.put_u8(flags);
+        body.put_slice(item.data());
+
+        self.write_frame(&body, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<CanAnyFrame> for CanFrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: CanAnyFrame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            CanAnyFrame::Normal(f) => self.encode(f, dst),
+            CanAnyFrame::Fd(f) => self.encode(f, dst),
+        }
     }
 }
 
 impl Decoder for CanFrameCodec {
-    type Item = CanFrame;
+    type Item = CanAnyFrame;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 5 {
-            return Ok(None);
-        }
+        if self.legacy {
+            if src.len() < LEGACY_HEADER_LEN {
+                return Ok(None);
+            }
+            let mut id_bytes = [0_u8; 4];
+            id_bytes.copy_from_slice(&src[..4]);
+            let can_id = u32::from_be_bytes(id_bytes);
+            let len = src[4] as usize;
+            let id_flags = IdFlags::from_bits_truncate(can_id);
+            // Remote frames carry the DLC in the length field but no trailing
+            // data bytes on the wire, so the payload size read off the stream
+            // is 0 for them.
+            let payload_len = if id_flags.contains(IdFlags::RTR) { 0 } else { len };
 
-        let mut can_frame = socketcan::frame::can_frame_default();
+            // Unlike the self-synchronizing format, legacy framing has no
+            // CRC or sync word to resync on, so a corrupt length byte can't
+            // be recovered from; surface it as an error instead of indexing
+            // past the classic 8-byte payload buffer.
+            if len > 8 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("legacy frame length {} exceeds classic CAN payload size", len),
+                ));
+            }
+
+            if src.len() < LEGACY_HEADER_LEN + payload_len {
+                src.reserve(LEGACY_HEADER_LEN + payload_len - src.len());
+                return Ok(None);
+            }
 
-        let mut id_bytes = [0_u8; 4];
-        id_bytes.copy_from_slice(&src[..4]);
-        can_frame.can_id = u32::from_be_bytes(id_bytes);
+            let mut body = Vec::with_capacity(BODY_HEADER_LEN + payload_len);
+            body.extend_from_slice(&src[..LEGACY_HEADER_LEN]);
+            body.push(0); // flags: legacy framing predates CAN FD, always classic
+            body.extend_from_slice(&src[LEGACY_HEADER_LEN..LEGACY_HEADER_LEN + payload_len]);
+            src.advance(LEGACY_HEADER_LEN + payload_len);
+            return Ok(decode_body(&body));
+        }
 
-        can_frame.can_dlc = src[4];
+        loop {
+            if src.len() < SYNC_HEADER_LEN {
+                return Ok(None);
+            }
 
-        let flags = IdFlags::from_bits_truncate(can_frame.can_id);
-        if flags.contains(IdFlags::RTR) {
-            src.advance(5);
+            if src[0] != SYNC_WORD[0] || src[1] != SYNC_WORD[1] {
+                // not aligned to a frame boundary: drop a byte and keep scanning
+                src.advance(1);
+                continue;
+            }
 
-            Ok(Some(CanFrame::from(can_frame)))
-        } else {
-            let len = can_frame.can_dlc as usize;
-            if src.len() < 5 + len {
-                // the full frame has not yet arrived
-                src.reserve(5 + len - src.len());
+            let body_len = src[2] as usize;
+            if body_len > BODY_HEADER_LEN + 64 {
+                // no valid body is this long, so the sync word matched by
+                // coincidence; resync without waiting for the bogus length
+                src.advance(1);
+                continue;
+            }
+            let frame_len = SYNC_HEADER_LEN + body_len + 1;
+            if src.len() < frame_len {
+                src.reserve(frame_len - src.len());
                 return Ok(None);
             }
-            can_frame.data[..len].copy_from_slice(&src[5..5 + len]);
 
-            // let data_frame = CanFrame::from_raw_id(can_id, data);
-            src.advance(5 + len);
-            Ok(Some(CanFrame::from(can_frame)))
+            let expected_crc = crc8(&src[2..SYNC_HEADER_LEN + body_len]);
+            if src[frame_len - 1] != expected_crc {
+                // the sync word matched by coincidence (or the frame is
+                // corrupt); resync by one byte and keep scanning
+                src.advance(1);
+                continue;
+            }
+
+            let body = src[SYNC_HEADER_LEN..SYNC_HEADER_LEN + body_len].to_vec();
+            match decode_body(&body) {
+                Some(frame) => {
+                    src.advance(frame_len);
+                    return Ok(Some(frame));
+                }
+                None => {
+                    // the CRC matched but the body itself is invalid (e.g. a
+                    // bogus length field); treat this as a false-positive
+                    // sync match too and keep scanning rather than consuming
+                    // and silently dropping the frame.
+                    src.advance(1);
+                    continue;
+                }
+            }
         }
     }
 }
@@ -85,48 +320,181 @@ mod test {
 
     #[test]
     fn test_encode_data_frame() {
-        let mut encoder = CanFrameCodec;
+        let mut encoder = CanFrameCodec::new();
         let can_frame = CanFrame::from_raw_id(10, &[1_u8, 2_u8, 3_u8]).unwrap();
         let mut dst = BytesMut::new();
         let r = encoder.encode(can_frame, &mut dst);
         assert!(r.is_ok());
 
-        assert_eq!(&[0_u8, 0_u8, 0_u8, 10_u8, 3_u8, 1_u8, 2_u8, 3_u8], &dst[..]);
+        assert_eq!(&SYNC_WORD, &dst[..2]);
+        assert_eq!(9_u8, dst[2]); // body length: 6-byte header + 3 bytes of data
+        assert_eq!(&[0_u8, 0_u8, 0_u8, 10_u8, 3_u8, 0_u8, 1_u8, 2_u8, 3_u8], &dst[3..12]);
     }
 
     #[test]
     fn test_decode_data_frame() {
-        let data = [0_u8, 0_u8, 0_u8, 10_u8, 3_u8, 1_u8, 2_u8, 3_u8];
-        let mut src = BytesMut::from(&data[..]);
-        let mut decoder = CanFrameCodec;
+        let mut encoder = CanFrameCodec::new();
+        let can_frame = CanFrame::from_raw_id(10, &[1_u8, 2_u8, 3_u8]).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(can_frame, &mut src).unwrap();
+
+        let mut decoder = CanFrameCodec::new();
         let r = decoder.decode(&mut src);
         assert!(r.is_ok());
-        let r = r.unwrap().unwrap();
+        let r = match r.unwrap().unwrap() {
+            CanAnyFrame::Normal(f) => f,
+            _ => panic!("expected a classic frame"),
+        };
         assert_eq!(10, r.id_word());
         assert_eq!(&[1_u8, 2_u8, 3_u8], r.data());
+        assert!(src.is_empty());
     }
 
     #[test]
     fn test_encode_remote_frame() {
-        let mut encoder = CanFrameCodec;
+        let mut encoder = CanFrameCodec::new();
         let can_frame = CanFrame::remote_from_raw_id(10, 3).unwrap();
         let mut dst = BytesMut::new();
         let r = encoder.encode(can_frame, &mut dst);
         assert!(r.is_ok());
 
-        assert_eq!(&[64_u8, 0_u8, 0_u8, 10_u8, 3_u8], &dst[..]);
+        assert_eq!(&SYNC_WORD, &dst[..2]);
+        assert_eq!(6_u8, dst[2]);
+        assert_eq!(&[64_u8, 0_u8, 0_u8, 10_u8, 3_u8, 0_u8], &dst[3..9]);
     }
 
     #[test]
     fn test_decode_remote_frame() {
-        let data = [64_u8, 0_u8, 0_u8, 10_u8, 3_u8, 1_u8, 2_u8, 3_u8];
-        let mut src = BytesMut::from(&data[..]);
-        let mut decoder = CanFrameCodec;
+        let mut encoder = CanFrameCodec::new();
+        let can_frame = CanFrame::remote_from_raw_id(10, 3).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(can_frame, &mut src).unwrap();
+
+        let mut decoder = CanFrameCodec::new();
         let r = match decoder.decode(&mut src) {
-            Ok(Some(CanFrame::Remote(r))) => r,
+            Ok(Some(CanAnyFrame::Normal(CanFrame::Remote(r)))) => r,
             _ => panic!(""),
         };
         assert_eq!(r.id(), Id::Standard(StandardId::new(10).unwrap()));
         assert_eq!(3, r.dlc());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_encode_fd_frame() {
+        let mut encoder = CanFrameCodec::new();
+        let data = [0_u8; 16];
+        let fd_frame = CanFdFrame::new(socketcan::StandardId::new(10).unwrap(), &data).unwrap();
+        let mut dst = BytesMut::new();
+        let r = encoder.encode(fd_frame, &mut dst);
+        assert!(r.is_ok());
+
+        assert_eq!(&SYNC_WORD, &dst[..2]);
+        assert_eq!(&[0_u8, 0_u8, 0_u8, 10_u8, 16_u8, FLAG_FD], &dst[3..9]);
+    }
+
+    #[test]
+    fn test_decode_fd_frame() {
+        let mut encoder = CanFrameCodec::new();
+        let data = [7_u8; 16];
+        let fd_frame = CanFdFrame::new(socketcan::StandardId::new(10).unwrap(), &data).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(fd_frame, &mut src).unwrap();
+
+        let mut decoder = CanFrameCodec::new();
+        let r = match decoder.decode(&mut src) {
+            Ok(Some(CanAnyFrame::Fd(f))) => f,
+            _ => panic!("expected an FD frame"),
+        };
+        assert_eq!(10, r.id_word());
+        assert_eq!(&[7_u8; 16], r.data());
+    }
+
+    #[test]
+    fn test_decode_resyncs_after_corruption() {
+        let mut encoder = CanFrameCodec::new();
+        let can_frame = CanFrame::from_raw_id(10, &[1_u8, 2_u8, 3_u8]).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(can_frame, &mut src).unwrap();
+
+        // splice in garbage bytes, including a spurious sync word, ahead of the real frame
+        let mut corrupted = BytesMut::from(&[0xaa_u8, 0x55_u8, 0xff_u8, 0x00_u8][..]);
+        corrupted.extend_from_slice(&src);
+
+        let mut decoder = CanFrameCodec::new();
+        let r = match decoder.decode(&mut corrupted) {
+            Ok(Some(CanAnyFrame::Normal(f))) => f,
+            other => panic!("expected to resync onto the real frame, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(10, r.id_word());
+        assert_eq!(&[1_u8, 2_u8, 3_u8], r.data());
+    }
+
+    #[test]
+    fn test_decode_resyncs_after_oversized_length_field() {
+        // A well-formed (correct CRC) frame whose length byte claims 9 bytes
+        // of classic-frame payload, which doesn't fit the 8-byte data array.
+        let bogus_body = [0_u8, 0_u8, 0_u8, 10_u8, 9_u8, 0_u8];
+        let mut corrupted = BytesMut::new();
+        corrupted.put_slice(&SYNC_WORD);
+        corrupted.put_u8(bogus_body.len() as u8);
+        corrupted.put_slice(&bogus_body);
+        corrupted.put_u8(crc8(&[&[bogus_body.len() as u8][..], &bogus_body].concat()));
+
+        let mut encoder = CanFrameCodec::new();
+        let can_frame = CanFrame::from_raw_id(20, &[4_u8, 5_u8, 6_u8]).unwrap();
+        encoder.encode(can_frame, &mut corrupted).unwrap();
+
+        let mut decoder = CanFrameCodec::new();
+        let r = match decoder.decode(&mut corrupted) {
+            Ok(Some(CanAnyFrame::Normal(f))) => f,
+            other => panic!("expected to resync past the bogus length onto the real frame, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(20, r.id_word());
+        assert_eq!(&[4_u8, 5_u8, 6_u8], r.data());
+    }
+
+    #[test]
+    fn test_legacy_decode_rejects_oversized_length_field() {
+        let mut src = BytesMut::from(&[0_u8, 0_u8, 0_u8, 10_u8, 9_u8][..]);
+        let mut decoder = CanFrameCodec::legacy();
+        assert!(decoder.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn test_legacy_round_trip() {
+        let mut encoder = CanFrameCodec::legacy();
+        let can_frame = CanFrame::from_raw_id(10, &[1_u8, 2_u8, 3_u8]).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(can_frame, &mut src).unwrap();
+
+        assert_eq!(&[0_u8, 0_u8, 0_u8, 10_u8, 3_u8, 1_u8, 2_u8, 3_u8], &src[..]);
+
+        let mut decoder = CanFrameCodec::legacy();
+        let r = match decoder.decode(&mut src) {
+            Ok(Some(CanAnyFrame::Normal(f))) => f,
+            _ => panic!("expected a classic frame"),
+        };
+        assert_eq!(10, r.id_word());
+        assert_eq!(&[1_u8, 2_u8, 3_u8], r.data());
+    }
+
+    #[test]
+    fn test_legacy_round_trip_remote_frame() {
+        let mut encoder = CanFrameCodec::legacy();
+        let can_frame = CanFrame::remote_from_raw_id(10, 3).unwrap();
+        let mut src = BytesMut::new();
+        encoder.encode(can_frame, &mut src).unwrap();
+
+        // header only: no trailing data bytes for a remote frame
+        assert_eq!(&[64_u8, 0_u8, 0_u8, 10_u8, 3_u8], &src[..]);
+
+        let mut decoder = CanFrameCodec::legacy();
+        let r = match decoder.decode(&mut src) {
+            Ok(Some(CanAnyFrame::Normal(CanFrame::Remote(r)))) => r,
+            other => panic!("expected a remote frame, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(r.id(), Id::Standard(StandardId::new(10).unwrap()));
+        assert_eq!(3, r.dlc());
+        assert!(src.is_empty());
+    }
+}
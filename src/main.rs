@@ -3,14 +3,30 @@ use clap::{Args, Parser, Subcommand};
 use futures::prelude::*;
 use futures::StreamExt;
 use socketcan::tokio::CanSocket;
-use socketcan::{EmbeddedFrame, Frame};
+use socketcan::{CanAnyFrame, EmbeddedFrame, Frame};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
-use tokio::net::{TcpListener, TcpStream};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, error, info};
 
+/// Depth of the CAN => TCP broadcast channel. Slow subscribers that fall this
+/// far behind lose the oldest frames rather than stalling the bus.
+const BROADCAST_CAPACITY: usize = 1024;
+/// Depth of the TCP => CAN injection queue shared by all clients.
+const INJECT_CAPACITY: usize = 256;
+
+mod candump;
+mod filter;
 mod frame;
+mod tls;
+
+/// Wall-clock time elapsed since the Unix epoch, for candump log timestamps.
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO)
+}
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -30,6 +46,9 @@ enum Commands {
 
     /// Listen for incoming TCP connection
     Listen(ListenArgs),
+
+    /// Replay a candump log file, honoring its recorded inter-frame timing
+    Replay(ReplayCmd),
 }
 
 #[derive(Args)]
@@ -38,8 +57,46 @@ struct ForwardCmd {
     #[arg(short, long, default_value = "can0")]
     interface: String,
 
-    /// host:port to stream to. i.e. 192.168.2.10:1234
-    dest: String,
+    /// host:port to stream to. i.e. 192.168.2.10:1234. Mutually exclusive with --unix.
+    dest: Option<String>,
+
+    /// connect to a Unix domain socket at this path instead of over TCP
+    #[arg(long, conflicts_with = "dest")]
+    unix: Option<String>,
+
+    /// wrap the TCP connection in TLS
+    #[arg(long, default_value_t = false)]
+    tls: bool,
+
+    /// server name to verify the peer's certificate against (defaults to the host in `dest`)
+    #[arg(long)]
+    tls_server_name: Option<String>,
+
+    /// PEM file of CA certificates to trust, in place of the bundled root store
+    #[arg(long)]
+    tls_ca: Option<String>,
+
+    /// accept any TLS certificate the peer presents, e.g. for self-signed test servers
+    #[arg(long, default_value_t = false)]
+    insecure: bool,
+
+    /// speak the older raw frame format (no sync word/CRC) for back-compat with a peer
+    /// that doesn't support self-synchronizing framing
+    #[arg(long, default_value_t = false)]
+    legacy_framing: bool,
+
+    /// CAN ID filter rule, ID:MASK in hex (e.g. 7e0:7f0), optionally prefixed with `!`
+    /// to invert it. May be given multiple times.
+    #[arg(long = "filter")]
+    filters: Vec<String>,
+
+    /// how `--filter` rules are interpreted
+    #[arg(long, default_value = "blacklist")]
+    filter_mode: filter::FilterMode,
+
+    /// tee every frame passing through the bridge to this file in candump log format
+    #[arg(long)]
+    log: Option<String>,
 }
 
 #[derive(Args)]
@@ -51,23 +108,116 @@ struct ListenArgs {
     /// listen socket
     #[arg(short, long, default_value = "0.0.0.0:10023")]
     socket: String,
+
+    /// listen on a Unix domain socket at this path instead of a TCP socket
+    #[arg(long, conflicts_with = "socket")]
+    unix: Option<String>,
+
+    /// PEM certificate chain to serve over TLS; requires --key
+    #[arg(long, requires = "key")]
+    cert: Option<String>,
+
+    /// PEM private key to serve over TLS; requires --cert
+    #[arg(long, requires = "cert")]
+    key: Option<String>,
+
+    /// speak the older raw frame format (no sync word/CRC) for back-compat with peers
+    /// that don't support self-synchronizing framing
+    #[arg(long, default_value_t = false)]
+    legacy_framing: bool,
+
+    /// CAN ID filter rule, ID:MASK in hex (e.g. 7e0:7f0), optionally prefixed with `!`
+    /// to invert it. May be given multiple times.
+    #[arg(long = "filter")]
+    filters: Vec<String>,
+
+    /// how `--filter` rules are interpreted
+    #[arg(long, default_value = "blacklist")]
+    filter_mode: filter::FilterMode,
+
+    /// tee every frame passing through the bridge to this file in candump log format
+    #[arg(long)]
+    log: Option<String>,
 }
 
-async fn pump_frames(mut tcp_stream: TcpStream, can_socket: &mut CanSocket) -> anyhow::Result<()> {
-    let (tcp_read, tcp_write) = tcp_stream.split();
-    let mut tcp_reader = FramedRead::new(tcp_read, frame::CanFrameCodec);
-    let mut tcp_writer = FramedWrite::new(tcp_write, frame::CanFrameCodec);
+#[derive(Args)]
+struct ReplayCmd {
+    /// candump log file to replay
+    log: String,
+
+    /// CAN interface to replay onto. Mutually exclusive with --dest/--unix.
+    #[arg(short, long)]
+    interface: Option<String>,
+
+    /// host:port to replay over TCP instead of onto a CAN interface
+    #[arg(long, conflicts_with = "interface")]
+    dest: Option<String>,
+
+    /// unix socket path to replay over instead of onto a CAN interface
+    #[arg(long, conflicts_with_all = ["interface", "dest"])]
+    unix: Option<String>,
+
+    /// speak the older raw frame format when replaying over --dest/--unix
+    #[arg(long, default_value_t = false)]
+    legacy_framing: bool,
+
+    /// playback speed multiplier, e.g. 2.0 replays twice as fast, 0.5 replays at half speed
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// loop the log file forever instead of replaying it once
+    #[arg(long, default_value_t = false)]
+    r#loop: bool,
+}
+
+fn build_filter(filter_mode: filter::FilterMode, filters: &[String]) -> anyhow::Result<filter::FrameFilter> {
+    if filters.is_empty() {
+        return Ok(filter::FrameFilter::allow_all());
+    }
+
+    let rules = filters
+        .iter()
+        .map(|s| filter::FilterRule::parse(s))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(filter::FrameFilter::new(filter_mode, rules))
+}
+
+fn codec_for(legacy: bool) -> frame::CanFrameCodec {
+    if legacy {
+        frame::CanFrameCodec::legacy()
+    } else {
+        frame::CanFrameCodec::new()
+    }
+}
+
+async fn pump_frames<S>(
+    stream: S,
+    can_socket: &mut CanSocket,
+    legacy_framing: bool,
+    inject_filter: &filter::FrameFilter,
+    logger: &mut Option<candump::CandumpWriter>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (tcp_read, tcp_write) = tokio::io::split(stream);
+    let mut tcp_reader = FramedRead::new(tcp_read, codec_for(legacy_framing));
+    let mut tcp_writer = FramedWrite::new(tcp_write, codec_for(legacy_framing));
 
     loop {
         tokio::select! {
             f = can_socket.next() => {
                 match f {
                     Some(Ok(f)) => {
-                        // debug!("CAN => TCP [{:x}]", f.id_word());
-                        if f.id_word() & 0x520 > 0 || f.id_word() & 0x5a0 > 0 {
-                            debug!("CAN => TCP UDS {:02x?} DATA {:02x?}", f.id_word(), f.data());
+                        if !inject_filter.allows(f.id_word()) {
+                            continue;
+                        }
+                        let f = CanAnyFrame::Normal(f);
+                        if let Some(logger) = logger {
+                            if let Err(e) = logger.log(&f, now()) {
+                                error!("error writing candump log: {}", e);
+                            }
                         }
-                        
                         if let Err(e) = tcp_writer.send(f).await {
                             error!("error sending to TCP: {}", e);
                         }
@@ -86,10 +236,14 @@ async fn pump_frames(mut tcp_stream: TcpStream, can_socket: &mut CanSocket) -> a
 
             f = tcp_reader.next() => {
                 match f {
-                    Some(Ok(f)) => {
-                        // debug!("TCP => CAN [{:x}]", f.id_word());
-                        if f.id_word() & 0x520 > 0 || f.id_word() & 0x5a0 > 0 {
-                            debug!("TCP => CAN UDS {:02x?} DATA {:02x?}", f.id_word(), f.data());
+                    Some(Ok(CanAnyFrame::Normal(f))) => {
+                        if !inject_filter.allows(f.id_word()) {
+                            continue;
+                        }
+                        if let Some(logger) = logger {
+                            if let Err(e) = logger.log(&CanAnyFrame::Normal(f.clone()), now()) {
+                                error!("error writing candump log: {}", e);
+                            }
                         }
                         if let Err(e) = can_socket.send(f).await {
                             error!("error sending frame: {}", e);
@@ -100,6 +254,12 @@ async fn pump_frames(mut tcp_stream: TcpStream, can_socket: &mut CanSocket) -> a
                         tokio::time::sleep(Duration::from_millis(10)).await;
                     }
 
+                    Some(Ok(CanAnyFrame::Fd(f))) => {
+                        // this bridge's local interface is a classic CAN socket, which
+                        // cannot transmit FD frames; drop it and log why locally.
+                        error!("dropping CAN FD frame [{:x}]: local CAN interface is classic CAN only", f.id_word());
+                    }
+
                     Some(Err(e)) => {
                         error!("{}", e);
                     }
@@ -115,8 +275,36 @@ async fn forward(cmd: ForwardCmd) -> anyhow::Result<()> {
     let mut can_socket = CanSocket::open(&cmd.interface)
         .with_context(|| format!("CAN interface: {}", cmd.interface))?;
 
-    let addrs: Vec<SocketAddr> = cmd
+    let inject_filter = build_filter(cmd.filter_mode, &cmd.filters)?;
+    let kernel_filters = inject_filter.kernel_filters();
+    if !kernel_filters.is_empty() {
+        can_socket
+            .set_filters(&kernel_filters)
+            .context("installing CAN filters")?;
+    }
+
+    let mut logger = cmd
+        .log
+        .as_deref()
+        .map(|path| candump::CandumpWriter::create(path, &cmd.interface))
+        .transpose()?;
+
+    if let Some(path) = &cmd.unix {
+        info!("connecting to unix socket {}", path);
+        let unix_stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("connecting to unix socket: {}", path))?;
+        info!("connected!");
+        pump_frames(unix_stream, &mut can_socket, cmd.legacy_framing, &inject_filter, &mut logger).await?;
+        return Ok(());
+    }
+
+    let dest = cmd
         .dest
+        .as_deref()
+        .context("either a host:port destination or --unix <path> is required")?;
+
+    let addrs: Vec<SocketAddr> = dest
         .to_socket_addrs()
         .expect("unable to resolve domain")
         .collect();
@@ -127,7 +315,22 @@ async fn forward(cmd: ForwardCmd) -> anyhow::Result<()> {
 
     let tcp_stream = TcpStream::connect(socket).await?;
     info!("connected!");
-    pump_frames(tcp_stream, &mut can_socket).await?;
+
+    if cmd.tls {
+        let connector = tls::build_connector(cmd.tls_ca.as_deref(), cmd.insecure)?;
+        let server_name = cmd
+            .tls_server_name
+            .clone()
+            .unwrap_or_else(|| dest.rsplit_once(':').map_or(dest.to_string(), |(h, _)| h.to_string()));
+        let server_name = server_name.try_into().context("invalid TLS server name")?;
+
+        info!("starting TLS handshake with {}", socket);
+        let tls_stream = connector.connect(server_name, tcp_stream).await?;
+        info!("TLS handshake complete");
+        pump_frames(tls_stream, &mut can_socket, cmd.legacy_framing, &inject_filter, &mut logger).await?;
+    } else {
+        pump_frames(tcp_stream, &mut can_socket, cmd.legacy_framing, &inject_filter, &mut logger).await?;
+    }
 
     Ok(())
 }
@@ -153,8 +356,137 @@ fn create_vcan(name: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Services a single accepted connection: writes every frame published on
+/// `frame_rx` out to the peer, and forwards every frame read from the peer
+/// into `inject_tx` for the CAN-writer task to drain. Returns once either
+/// side of the connection closes.
+async fn handle_client<S>(
+    stream: S,
+    mut frame_rx: broadcast::Receiver<CanAnyFrame>,
+    inject_tx: mpsc::Sender<CanAnyFrame>,
+    legacy_framing: bool,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (tcp_read, tcp_write) = tokio::io::split(stream);
+    let mut tcp_reader = FramedRead::new(tcp_read, codec_for(legacy_framing));
+    let mut tcp_writer = FramedWrite::new(tcp_write, codec_for(legacy_framing));
+
+    loop {
+        tokio::select! {
+            f = frame_rx.recv() => {
+                match f {
+                    Ok(f) => {
+                        if let Err(e) = tcp_writer.send(f).await {
+                            error!("error sending to TCP: {}", e);
+                            break;
+                        }
+                        if let Err(e) = tcp_writer.flush().await {
+                            error!("error flushing TCP: {}", e);
+                        }
+                    }
+
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        debug!("client fell behind, dropped {} frames", skipped);
+                    }
+
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            },
+
+            f = tcp_reader.next() => {
+                match f {
+                    Some(Ok(f)) => {
+                        if inject_tx.send(f).await.is_err() {
+                            break;
+                        }
+                    }
+
+                    Some(Err(e)) => {
+                        error!("{}", e);
+                        break;
+                    }
+
+                    None => break,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Owns the `CanSocket` for the lifetime of the `listen` server: publishes
+/// every frame read off the bus to `frame_tx`, and writes every frame
+/// received on `inject_rx` (from any connected client) onto the bus.
+async fn run_can_bus(
+    mut can_socket: CanSocket,
+    frame_tx: broadcast::Sender<CanAnyFrame>,
+    mut inject_rx: mpsc::Receiver<CanAnyFrame>,
+    inject_filter: filter::FrameFilter,
+    mut logger: Option<candump::CandumpWriter>,
+) {
+    loop {
+        tokio::select! {
+            f = can_socket.next() => {
+                match f {
+                    Some(Ok(f)) => {
+                        if !inject_filter.allows(f.id_word()) {
+                            continue;
+                        }
+                        let f = CanAnyFrame::Normal(f);
+                        if let Some(logger) = &mut logger {
+                            if let Err(e) = logger.log(&f, now()) {
+                                error!("error writing candump log: {}", e);
+                            }
+                        }
+                        // no subscribers is not an error: frames are simply dropped
+                        let _ = frame_tx.send(f);
+                    }
+
+                    Some(Err(e)) => {
+                        error!("CAN io error: {}", e);
+                    }
+
+                    None => {
+                        error!("CAN socket closed");
+                        break;
+                    }
+                }
+            },
+
+            Some(f) = inject_rx.recv() => {
+                match f {
+                    CanAnyFrame::Normal(f) => {
+                        if !inject_filter.allows(f.id_word()) {
+                            continue;
+                        }
+                        if let Some(logger) = &mut logger {
+                            if let Err(e) = logger.log(&CanAnyFrame::Normal(f.clone()), now()) {
+                                error!("error writing candump log: {}", e);
+                            }
+                        }
+                        if let Err(e) = can_socket.send(f).await {
+                            error!("error sending frame: {}", e);
+                        }
+                        if let Err(e) = can_socket.flush().await {
+                            error!("error flushing CAN socket: {}", e);
+                        }
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+
+                    CanAnyFrame::Fd(f) => {
+                        error!("dropping CAN FD frame [{:x}]: local CAN interface is classic CAN only", f.id_word());
+                    }
+                }
+            }
+        }
+    }
+}
+
 async fn listen(cmd: ListenArgs) -> anyhow::Result<()> {
-    let mut can_socket = match CanSocket::open(&cmd.interface) {
+    let can_socket = match CanSocket::open(&cmd.interface) {
         Ok(s) => s,
         Err(e) => {
             error!("unable to open CAN socket: {}: {}", cmd.interface, e);
@@ -163,13 +495,165 @@ async fn listen(cmd: ListenArgs) -> anyhow::Result<()> {
         }
     };
 
+    let inject_filter = build_filter(cmd.filter_mode, &cmd.filters)?;
+    let kernel_filters = inject_filter.kernel_filters();
+    if !kernel_filters.is_empty() {
+        can_socket
+            .set_filters(&kernel_filters)
+            .context("installing CAN filters")?;
+    }
+
+    let acceptor = match (&cmd.cert, &cmd.key) {
+        (Some(cert), Some(key)) => Some(tls::build_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    let logger = cmd
+        .log
+        .as_deref()
+        .map(|path| candump::CandumpWriter::create(path, &cmd.interface))
+        .transpose()?;
+
+    let (frame_tx, _) = broadcast::channel::<CanAnyFrame>(BROADCAST_CAPACITY);
+    let (inject_tx, inject_rx) = mpsc::channel::<CanAnyFrame>(INJECT_CAPACITY);
+    tokio::spawn(run_can_bus(can_socket, frame_tx.clone(), inject_rx, inject_filter, logger));
+
+    if let Some(path) = &cmd.unix {
+        // a stale socket file from a previous run would otherwise make bind() fail
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("removing stale socket: {}", path)),
+        }
+
+        let unix_listener = UnixListener::bind(path)
+            .with_context(|| format!("binding unix socket: {}", path))?;
+        info!("listening on unix socket: {}", path);
+        loop {
+            let (unix_stream, _) = unix_listener.accept().await?;
+            info!("incoming connection on {}", path);
+
+            let frame_rx = frame_tx.subscribe();
+            let inject_tx = inject_tx.clone();
+            let legacy_framing = cmd.legacy_framing;
+
+            tokio::spawn(async move {
+                if let Err(e) = handle_client(unix_stream, frame_rx, inject_tx, legacy_framing).await {
+                    error!("client disconnected: {}", e);
+                }
+            });
+        }
+    }
+
     let tcp_listener = TcpListener::bind(&cmd.socket).await?;
     info!("listening on: {}", cmd.socket);
     loop {
         let (tcp_stream, addr) = tcp_listener.accept().await?;
         info!("incoming connection from: {}", addr);
 
-        pump_frames(tcp_stream, &mut can_socket).await?;
+        let frame_rx = frame_tx.subscribe();
+        let inject_tx = inject_tx.clone();
+        let acceptor = acceptor.clone();
+        let legacy_framing = cmd.legacy_framing;
+
+        tokio::spawn(async move {
+            let result = if let Some(acceptor) = acceptor {
+                match acceptor.accept(tcp_stream).await {
+                    Ok(tls_stream) => handle_client(tls_stream, frame_rx, inject_tx, legacy_framing).await,
+                    Err(e) => {
+                        error!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                }
+            } else {
+                handle_client(tcp_stream, frame_rx, inject_tx, legacy_framing).await
+            };
+
+            if let Err(e) = result {
+                error!("client {} disconnected: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Where a replayed log's frames are sent: either straight onto a local CAN
+/// interface, or encoded over a TCP/Unix stream exactly as `forward` would.
+enum ReplaySink {
+    Can(CanSocket),
+    Stream(FramedWrite<Box<dyn AsyncWrite + Unpin + Send>, frame::CanFrameCodec>),
+}
+
+impl ReplaySink {
+    async fn send(&mut self, frame: CanAnyFrame) -> anyhow::Result<()> {
+        match self {
+            ReplaySink::Can(can_socket) => match frame {
+                CanAnyFrame::Normal(f) => {
+                    can_socket.send(f).await?;
+                    can_socket.flush().await?;
+                }
+                CanAnyFrame::Fd(f) => {
+                    error!("dropping CAN FD frame [{:x}]: local CAN interface is classic CAN only", f.id_word());
+                }
+            },
+            ReplaySink::Stream(writer) => {
+                writer.send(frame).await?;
+                writer.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn replay(cmd: ReplayCmd) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(&cmd.log).with_context(|| format!("reading log file: {}", cmd.log))?;
+    let entries = contents
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(candump::parse_line)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut sink = if let Some(interface) = &cmd.interface {
+        ReplaySink::Can(CanSocket::open(interface).with_context(|| format!("CAN interface: {}", interface))?)
+    } else if let Some(path) = &cmd.unix {
+        info!("connecting to unix socket {}", path);
+        let unix_stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("connecting to unix socket: {}", path))?;
+        let boxed: Box<dyn AsyncWrite + Unpin + Send> = Box::new(unix_stream);
+        ReplaySink::Stream(FramedWrite::new(boxed, codec_for(cmd.legacy_framing)))
+    } else if let Some(dest) = &cmd.dest {
+        let addr = dest
+            .to_socket_addrs()
+            .with_context(|| format!("resolving dest: {}", dest))?
+            .next()
+            .with_context(|| format!("no addresses for dest: {}", dest))?;
+        info!("connecting to {}", addr);
+        let tcp_stream = TcpStream::connect(addr).await?;
+        let boxed: Box<dyn AsyncWrite + Unpin + Send> = Box::new(tcp_stream);
+        ReplaySink::Stream(FramedWrite::new(boxed, codec_for(cmd.legacy_framing)))
+    } else {
+        anyhow::bail!("one of --interface, --dest or --unix is required");
+    };
+
+    loop {
+        let mut prev_timestamp = None;
+        for entry in &entries {
+            if let Some(prev) = prev_timestamp {
+                let delta = entry.timestamp.saturating_sub(prev);
+                if !delta.is_zero() {
+                    tokio::time::sleep(delta.div_f64(cmd.speed)).await;
+                }
+            }
+            prev_timestamp = Some(entry.timestamp);
+
+            if let Err(e) = sink.send(entry.frame.clone()).await {
+                error!("error replaying frame: {}", e);
+            }
+        }
+
+        if !cmd.r#loop {
+            break;
+        }
     }
 
     Ok(())
@@ -194,6 +678,8 @@ async fn main() -> anyhow::Result<()> {
         Commands::Forward(cmd) => forward(cmd).await?,
 
         Commands::Listen(cmd) => listen(cmd).await?,
+
+        Commands::Replay(cmd) => replay(cmd).await?,
     }
 
     Ok(())
@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Accepts any server certificate without validation. Only wired up behind
+/// `--insecure`, for talking to self-signed test/dev servers.
+#[derive(Debug)]
+struct NoCertVerification(Arc<tokio_rustls::rustls::crypto::CryptoProvider>);
+
+impl ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        tokio_rustls::rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        tokio_rustls::rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Build a `TlsConnector` for the `Forward` side. With `insecure` set, any
+/// server certificate is accepted (for talking to self-signed test servers);
+/// otherwise `ca_path` (if given) is trusted in place of the bundled
+/// `webpki_roots` root store, which is used only when no `ca_path` is given.
+pub fn build_connector(ca_path: Option<&str>, insecure: bool) -> anyhow::Result<TlsConnector> {
+    // Build explicitly from a known provider rather than `ClientConfig::builder()`,
+    // which relies on a process-wide default `CryptoProvider` having been installed.
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+
+    let config = if insecure {
+        ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("selecting TLS protocol versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        if let Some(ca_path) = ca_path {
+            let file = File::open(ca_path).with_context(|| format!("opening CA file: {}", ca_path))?;
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                roots.add(cert.with_context(|| format!("reading CA file: {}", ca_path))?)?;
+            }
+        } else {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .context("selecting TLS protocol versions")?
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Build a `TlsAcceptor` for the `Listen` side from a PEM certificate chain
+/// and private key.
+pub fn build_acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let cert_file =
+        File::open(cert_path).with_context(|| format!("opening TLS cert: {}", cert_path))?;
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("reading TLS cert: {}", cert_path))?;
+
+    let key_file = File::open(key_path).with_context(|| format!("opening TLS key: {}", key_path))?;
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .with_context(|| format!("reading TLS key: {}", key_path))?
+        .with_context(|| format!("no private key found in: {}", key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("building TLS server config")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}